@@ -0,0 +1,8 @@
+use crate::userstore::UserId;
+
+/// The authenticated user attached to a request by the JWT auth middleware.
+#[derive(Debug, Clone)]
+pub struct JWTUser {
+    pub id: UserId,
+    pub username: String,
+}
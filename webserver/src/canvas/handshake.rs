@@ -0,0 +1,34 @@
+use std::collections::HashSet;
+
+use serde::Deserialize;
+
+/// The first message a client must send after the socket opens, declaring which layers it
+/// wants to receive events for. Anything else received before this handshake is rejected.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientHandshake {
+    Subscribe { layers: HashSet<u16> },
+}
+
+/// What a participant has subscribed to, used to filter broadcasts before they're enqueued for
+/// delivery. An empty `layers` set means "everything" (e.g. for a client viewing the whole
+/// canvas rather than a specific region).
+#[derive(Debug, Clone)]
+pub struct Subscription {
+    pub layers: HashSet<u16>,
+}
+
+impl From<ClientHandshake> for Subscription {
+    fn from(handshake: ClientHandshake) -> Self {
+        match handshake {
+            ClientHandshake::Subscribe { layers } => Self { layers },
+        }
+    }
+}
+
+impl Subscription {
+    /// Whether a binary op targeting `layer_id` should be delivered to this participant.
+    pub fn wants_layer(&self, layer_id: u16) -> bool {
+        self.layers.is_empty() || self.layers.contains(&layer_id)
+    }
+}
@@ -0,0 +1,6 @@
+pub mod handshake;
+pub mod outbound_queue;
+pub mod proto;
+pub mod server;
+pub mod socket_handler;
+pub mod store;
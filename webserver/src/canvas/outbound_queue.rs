@@ -0,0 +1,129 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use tokio::sync::Notify;
+
+use crate::userstore::UserId;
+
+use super::server::OutboundMessage;
+
+/// Identifies a class of event whose queued-but-undelivered copy can be replaced by a newer one
+/// instead of appended, because only the latest value matters to the receiver (e.g. a cursor
+/// position or an in-progress stroke preview). Events without a key (chat, finalized strokes)
+/// always append in order.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ReplaceKey {
+    Cursor(UserId),
+    StrokePreview(UserId, u32),
+}
+
+struct Entry {
+    replace_key: Option<ReplaceKey>,
+    msg: OutboundMessage,
+}
+
+/// A per-participant outbound queue with the same bounded, evict-on-full semantics as a bounded
+/// channel, plus coalescing: enqueuing an entry tagged with a [`ReplaceKey`] that's still unread
+/// overwrites that entry in place instead of appending, so a slow client doesn't pay for every
+/// intermediate cursor/stroke-preview update queued behind it, only the latest one.
+pub struct OutboundQueue {
+    capacity: usize,
+    entries: Mutex<VecDeque<Entry>>,
+    notify: Notify,
+}
+
+impl OutboundQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Enqueues `msg`, coalescing it into an existing entry with the same `replace_key` if one
+    /// is still unread. Returns `false` instead of appending if the queue is full and `msg`
+    /// couldn't be coalesced, mirroring `mpsc::Sender::try_send`'s full-channel behavior so
+    /// callers can evict a participant that's fallen too far behind.
+    pub fn try_send(&self, msg: OutboundMessage, replace_key: Option<ReplaceKey>) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+
+        if let Some(key) = &replace_key {
+            if let Some(existing) = entries.iter_mut().find(|entry| entry.replace_key.as_ref() == Some(key)) {
+                existing.msg = msg;
+                return true;
+            }
+        }
+
+        if entries.len() >= self.capacity {
+            return false;
+        }
+
+        entries.push_back(Entry { replace_key, msg });
+        drop(entries);
+        self.notify.notify_one();
+
+        true
+    }
+
+    /// Waits for and pops the next queued message.
+    pub async fn recv(&self) -> OutboundMessage {
+        loop {
+            if let Some(entry) = self.entries.lock().unwrap().pop_front() {
+                return entry.msg;
+            }
+
+            self.notify.notified().await;
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> OutboundMessage {
+        OutboundMessage::Text(s.to_owned())
+    }
+
+    #[tokio::test]
+    async fn matching_replace_key_overwrites_in_place() {
+        let queue = OutboundQueue::new(2);
+        let key = ReplaceKey::Cursor(UserId("alice".to_owned()));
+
+        assert!(queue.try_send(text("first"), Some(key.clone())));
+        assert!(queue.try_send(text("second"), Some(key)));
+        assert_eq!(queue.len(), 1);
+
+        assert!(matches!(queue.recv().await, OutboundMessage::Text(s) if s == "second"));
+    }
+
+    #[tokio::test]
+    async fn entries_without_a_matching_key_are_appended() {
+        let queue = OutboundQueue::new(2);
+        let key = ReplaceKey::Cursor(UserId("alice".to_owned()));
+
+        assert!(queue.try_send(text("first"), Some(key)));
+        assert!(queue.try_send(text("second"), None));
+        assert_eq!(queue.len(), 2);
+
+        assert!(matches!(queue.recv().await, OutboundMessage::Text(s) if s == "first"));
+        assert!(matches!(queue.recv().await, OutboundMessage::Text(s) if s == "second"));
+    }
+
+    #[tokio::test]
+    async fn non_coalescable_send_fails_once_at_capacity() {
+        let queue = OutboundQueue::new(1);
+
+        assert!(queue.try_send(text("first"), None));
+        assert!(!queue.try_send(text("second"), None));
+        assert_eq!(queue.len(), 1);
+
+        assert!(matches!(queue.recv().await, OutboundMessage::Text(s) if s == "first"));
+    }
+}
@@ -0,0 +1,86 @@
+use crate::userstore::UserId;
+
+use super::outbound_queue::ReplaceKey;
+
+/// Wire format for high-frequency canvas drawing operations (stroke points, cursor moves, ...).
+/// These arrive far more often than control/chat messages, so they're kept off the JSON text
+/// path and sent as compact binary frames instead: a 1-byte op kind, a 2-byte layer id, a
+/// 4-byte stroke id for ops that belong to a stroke, then an op-specific payload that we don't
+/// need to understand to relay it to other participants.
+const CURSOR_HEADER_LEN: usize = 3;
+const STROKE_HEADER_LEN: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanvasOpKind {
+    StrokePoint,
+    StrokeEnd,
+    CursorMove,
+}
+
+impl CanvasOpKind {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0x01 => Some(Self::StrokePoint),
+            0x02 => Some(Self::StrokeEnd),
+            0x03 => Some(Self::CursorMove),
+            _ => None,
+        }
+    }
+}
+
+/// The fixed-size header every binary canvas op carries.
+#[derive(Debug)]
+pub struct CanvasOpHeader {
+    pub kind: CanvasOpKind,
+    pub layer_id: u16,
+    /// Identifies the stroke this op belongs to. Absent for ops that aren't part of a stroke
+    /// (currently just cursor moves).
+    pub stroke_id: Option<u32>,
+}
+
+#[derive(Debug)]
+pub enum CanvasOpError {
+    TooShort,
+    UnknownOpKind(u8),
+}
+
+impl CanvasOpHeader {
+    /// Parses just the header of a binary canvas op frame, to validate it's well-formed before
+    /// relaying it. The op-specific payload after the header is forwarded byte-for-byte without
+    /// needing to be understood here.
+    pub fn parse(bytes: &[u8]) -> Result<Self, CanvasOpError> {
+        if bytes.len() < CURSOR_HEADER_LEN {
+            return Err(CanvasOpError::TooShort);
+        }
+
+        let kind = CanvasOpKind::from_byte(bytes[0]).ok_or(CanvasOpError::UnknownOpKind(bytes[0]))?;
+        let layer_id = u16::from_be_bytes([bytes[1], bytes[2]]);
+
+        let stroke_id = match kind {
+            CanvasOpKind::CursorMove => None,
+            CanvasOpKind::StrokePoint | CanvasOpKind::StrokeEnd => {
+                if bytes.len() < STROKE_HEADER_LEN {
+                    return Err(CanvasOpError::TooShort);
+                }
+
+                Some(u32::from_be_bytes([bytes[3], bytes[4], bytes[5], bytes[6]]))
+            }
+        };
+
+        Ok(Self { kind, layer_id, stroke_id })
+    }
+
+    /// The key this op's broadcast can be coalesced under, or `None` if it must always be
+    /// appended in order: a cursor move only matters as its latest position, and an in-progress
+    /// stroke point is superseded by the next point on the same stroke, but a finalized stroke
+    /// is a durable edit to the canvas and is never dropped in favor of a newer one.
+    pub fn replace_key(&self, author: &UserId) -> Option<ReplaceKey> {
+        match self.kind {
+            CanvasOpKind::CursorMove => Some(ReplaceKey::Cursor(author.clone())),
+            CanvasOpKind::StrokePoint => {
+                self.stroke_id.map(|stroke_id| ReplaceKey::StrokePreview(author.clone(), stroke_id))
+            }
+            CanvasOpKind::StrokeEnd => None,
+        }
+    }
+}
@@ -0,0 +1,466 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_ws::{CloseCode, CloseReason};
+use bytes::Bytes;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::userstore::UserId;
+
+use super::handshake::Subscription;
+use super::outbound_queue::{OutboundQueue, ReplaceKey};
+use super::store::{CanvasId, CanvasRole};
+
+/// Capacity of a participant's outbound queue before they're considered a slow consumer and
+/// disconnected, rather than letting them stall the whole canvas's broadcasts.
+pub const OUTBOUND_QUEUE_CAPACITY: usize = 200;
+
+/// An event queued for delivery to a participant. Control/chat traffic goes over `Text`; the
+/// high-frequency drawing ops added in `proto` go over `Binary` to keep their encoding compact.
+#[derive(Debug, Clone)]
+pub enum OutboundMessage {
+    Text(String),
+    Binary(Bytes),
+}
+
+struct Participant {
+    queue: Arc<OutboundQueue>,
+    evict_tx: oneshot::Sender<CloseReason>,
+    username: String,
+    role: CanvasRole,
+    /// Most recently measured round-trip latency to this participant, if any.
+    latency: Option<Duration>,
+    /// `None` until the participant completes the subscribe handshake; broadcasts aren't
+    /// delivered to a participant until this is set.
+    subscription: Option<Subscription>,
+}
+
+enum Command {
+    Connect {
+        canvas_id: CanvasId,
+        user_id: UserId,
+        username: String,
+        role: CanvasRole,
+        queue: Arc<OutboundQueue>,
+        evict_tx: oneshot::Sender<CloseReason>,
+        res_tx: oneshot::Sender<()>,
+    },
+
+    Disconnect {
+        canvas_id: CanvasId,
+        user_id: UserId,
+    },
+
+    BroadcastEvent {
+        canvas_id: CanvasId,
+        user_id: UserId,
+        msg: String,
+        res_tx: oneshot::Sender<()>,
+    },
+
+    BroadcastBinaryEvent {
+        canvas_id: CanvasId,
+        user_id: UserId,
+        layer_id: u16,
+        replace_key: Option<ReplaceKey>,
+        payload: Bytes,
+        res_tx: oneshot::Sender<()>,
+    },
+
+    Subscribe {
+        canvas_id: CanvasId,
+        user_id: UserId,
+        subscription: Subscription,
+        res_tx: oneshot::Sender<()>,
+    },
+
+    ReportLatency {
+        canvas_id: CanvasId,
+        user_id: UserId,
+        latency: Duration,
+    },
+
+    IsConnected {
+        canvas_id: CanvasId,
+        user_id: UserId,
+        res_tx: oneshot::Sender<bool>,
+    },
+
+    GetLatency {
+        canvas_id: CanvasId,
+        user_id: UserId,
+        res_tx: oneshot::Sender<Option<Duration>>,
+    },
+}
+
+/// Holds every canvas and the participants currently connected to it, and broadcasts drawing
+/// events between the participants of the same canvas.
+pub struct CanvasSocketServer {
+    canvases: HashMap<CanvasId, HashMap<UserId, Participant>>,
+    cmd_rx: mpsc::UnboundedReceiver<Command>,
+}
+
+impl CanvasSocketServer {
+    pub fn new() -> (Self, CanvasSocketServerHandle) {
+        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+
+        (
+            Self {
+                canvases: HashMap::new(),
+                cmd_rx,
+            },
+            CanvasSocketServerHandle { cmd_tx },
+        )
+    }
+
+    /// Sends `msg` to every other participant of `canvas_id` who has completed the subscribe
+    /// handshake and, for `layer_id`-scoped events, whose subscription covers that layer. If
+    /// `replace_key` is set and a still-unread copy of it is already queued for a participant,
+    /// that copy is overwritten in place instead of appending; otherwise a participant whose
+    /// outbound queue is full has fallen too far behind to keep up with the room and is evicted
+    /// instead of blocking delivery to everyone else.
+    fn broadcast_event(
+        &mut self,
+        canvas_id: &CanvasId,
+        from: &UserId,
+        msg: OutboundMessage,
+        layer_id: Option<u16>,
+        replace_key: Option<ReplaceKey>,
+    ) {
+        let Some(participants) = self.canvases.get_mut(canvas_id) else {
+            return;
+        };
+
+        let mut fallen_behind = Vec::new();
+
+        for (user_id, participant) in participants.iter() {
+            if user_id == from {
+                continue;
+            }
+
+            let Some(subscription) = &participant.subscription else {
+                continue;
+            };
+
+            if layer_id.is_some_and(|layer_id| !subscription.wants_layer(layer_id)) {
+                continue;
+            }
+
+            if !participant.queue.try_send(msg.clone(), replace_key.clone()) {
+                fallen_behind.push(user_id.clone());
+            }
+        }
+
+        for user_id in fallen_behind {
+            println!("participant {user_id} in {canvas_id} fell behind and is being disconnected");
+
+            if let Some(participant) = participants.remove(&user_id) {
+                let _ = participant.evict_tx.send(CloseReason {
+                    code: CloseCode::Policy,
+                    description: Some("client fell behind and was disconnected".to_owned()),
+                });
+            }
+        }
+    }
+
+    pub async fn run(mut self) {
+        while let Some(cmd) = self.cmd_rx.recv().await {
+            match cmd {
+                Command::Connect {
+                    canvas_id,
+                    user_id,
+                    username,
+                    role,
+                    queue,
+                    evict_tx,
+                    res_tx,
+                } => {
+                    println!("{username} ({user_id}) joined {canvas_id} as {role:?}");
+
+                    self.canvases.entry(canvas_id).or_default().insert(
+                        user_id,
+                        Participant {
+                            queue,
+                            evict_tx,
+                            username,
+                            role,
+                            latency: None,
+                            subscription: None,
+                        },
+                    );
+
+                    let _ = res_tx.send(());
+                }
+
+                Command::Disconnect { canvas_id, user_id } => {
+                    if let Some(participants) = self.canvases.get_mut(&canvas_id) {
+                        participants.remove(&user_id);
+
+                        if participants.is_empty() {
+                            self.canvases.remove(&canvas_id);
+                        }
+                    }
+                }
+
+                Command::BroadcastEvent { canvas_id, user_id, msg, res_tx } => {
+                    // chat is a durable event: never coalesced, always appended in order
+                    self.broadcast_event(&canvas_id, &user_id, OutboundMessage::Text(msg), None, None);
+                    let _ = res_tx.send(());
+                }
+
+                Command::BroadcastBinaryEvent { canvas_id, user_id, layer_id, replace_key, payload, res_tx } => {
+                    self.broadcast_event(&canvas_id, &user_id, OutboundMessage::Binary(payload), Some(layer_id), replace_key);
+                    let _ = res_tx.send(());
+                }
+
+                Command::Subscribe { canvas_id, user_id, subscription, res_tx } => {
+                    if let Some(participant) = self
+                        .canvases
+                        .get_mut(&canvas_id)
+                        .and_then(|participants| participants.get_mut(&user_id))
+                    {
+                        participant.subscription = Some(subscription);
+                    }
+
+                    let _ = res_tx.send(());
+                }
+
+                Command::ReportLatency { canvas_id, user_id, latency } => {
+                    if let Some(participant) = self
+                        .canvases
+                        .get_mut(&canvas_id)
+                        .and_then(|participants| participants.get_mut(&user_id))
+                    {
+                        participant.latency = Some(latency);
+                    }
+                }
+
+                Command::IsConnected { canvas_id, user_id, res_tx } => {
+                    let connected = self
+                        .canvases
+                        .get(&canvas_id)
+                        .is_some_and(|participants| participants.contains_key(&user_id));
+
+                    let _ = res_tx.send(connected);
+                }
+
+                Command::GetLatency { canvas_id, user_id, res_tx } => {
+                    let latency = self
+                        .canvases
+                        .get(&canvas_id)
+                        .and_then(|participants| participants.get(&user_id))
+                        .and_then(|participant| participant.latency);
+
+                    let _ = res_tx.send(latency);
+                }
+            }
+        }
+    }
+}
+
+/// Handle used by connection tasks to talk to the [`CanvasSocketServer`].
+#[derive(Debug, Clone)]
+pub struct CanvasSocketServerHandle {
+    cmd_tx: mpsc::UnboundedSender<Command>,
+}
+
+impl CanvasSocketServerHandle {
+    /// Registers a new participant's outbound queue for `canvas_id`. `evict_tx` is fired by the
+    /// server if this participant is later disconnected for falling too far behind, so the
+    /// connection task can close the socket with the right reason.
+    pub async fn connect(
+        &self,
+        queue: Arc<OutboundQueue>,
+        evict_tx: oneshot::Sender<CloseReason>,
+        canvas_id: CanvasId,
+        user_id: UserId,
+        username: String,
+        role: CanvasRole,
+    ) {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::Connect {
+                canvas_id,
+                user_id,
+                username,
+                role,
+                queue,
+                evict_tx,
+                res_tx,
+            })
+            .expect("canvas server should be running");
+
+        res_rx.await.expect("canvas server should not drop connect response");
+    }
+
+    /// Removes a participant from a canvas.
+    pub fn disconnect(&self, canvas_id: CanvasId, user_id: UserId) {
+        let _ = self.cmd_tx.send(Command::Disconnect { canvas_id, user_id });
+    }
+
+    /// Broadcasts a text event to every other participant of `canvas_id` who has completed the
+    /// subscribe handshake.
+    pub async fn broadcast_event(&self, canvas_id: CanvasId, user_id: UserId, msg: impl Into<String>) {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::BroadcastEvent {
+                canvas_id,
+                user_id,
+                msg: msg.into(),
+                res_tx,
+            })
+            .expect("canvas server should be running");
+
+        res_rx.await.expect("canvas server should not drop broadcast response");
+    }
+
+    /// Broadcasts a binary drawing-op frame to every other participant of `canvas_id` whose
+    /// subscription covers `layer_id`; participants that haven't subscribed yet get nothing. Kept
+    /// separate from [`Self::broadcast_event`] so high-frequency stroke/cursor traffic never
+    /// has to round-trip through JSON encoding. `replace_key`, if set, lets a slow participant's
+    /// queue coalesce this event with a still-unread one instead of growing.
+    pub async fn broadcast_binary_event(
+        &self,
+        canvas_id: CanvasId,
+        user_id: UserId,
+        layer_id: u16,
+        replace_key: Option<ReplaceKey>,
+        payload: Bytes,
+    ) {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::BroadcastBinaryEvent {
+                canvas_id,
+                user_id,
+                layer_id,
+                replace_key,
+                payload,
+                res_tx,
+            })
+            .expect("canvas server should be running");
+
+        res_rx.await.expect("canvas server should not drop broadcast response");
+    }
+
+    /// Records a participant's subscribe handshake. Until this is called for a participant, no
+    /// broadcasts are delivered to them.
+    pub async fn subscribe(&self, canvas_id: CanvasId, user_id: UserId, subscription: Subscription) {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::Subscribe {
+                canvas_id,
+                user_id,
+                subscription,
+                res_tx,
+            })
+            .expect("canvas server should be running");
+
+        res_rx.await.expect("canvas server should not drop subscribe response");
+    }
+
+    /// Records the latest measured round-trip latency for a participant, so connection quality
+    /// can be surfaced per user and used to adapt broadcast behavior.
+    pub fn report_latency(&self, canvas_id: CanvasId, user_id: UserId, latency: Duration) {
+        let _ = self.cmd_tx.send(Command::ReportLatency { canvas_id, user_id, latency });
+    }
+
+    /// Whether `user_id` is currently a registered participant of `canvas_id`.
+    pub async fn is_connected(&self, canvas_id: CanvasId, user_id: UserId) -> bool {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::IsConnected { canvas_id, user_id, res_tx })
+            .expect("canvas server should be running");
+
+        res_rx.await.expect("canvas server should not drop is_connected response")
+    }
+
+    /// The most recently measured round-trip latency for `user_id` in `canvas_id`, or `None` if
+    /// they're not connected or no heartbeat round trip has completed yet.
+    pub async fn latency(&self, canvas_id: CanvasId, user_id: UserId) -> Option<Duration> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::GetLatency { canvas_id, user_id, res_tx })
+            .expect("canvas server should be running");
+
+        res_rx.await.expect("canvas server should not drop get_latency response")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A participant whose outbound queue fills up (the connection task can't write to the
+    /// socket fast enough, or simply never reads) is evicted with a `CloseReason` rather than
+    /// left to silently stall every broadcast to the rest of the canvas.
+    #[tokio::test]
+    async fn fallen_behind_participant_is_evicted_with_policy_close() {
+        let (server, handle) = CanvasSocketServer::new();
+        tokio::spawn(server.run());
+
+        let canvas_id = CanvasId("canvas-1".to_owned());
+        let sender_id = UserId("sender".to_owned());
+        let slow_id = UserId("slow".to_owned());
+
+        let sender_queue = Arc::new(OutboundQueue::new(OUTBOUND_QUEUE_CAPACITY));
+        let (sender_evict_tx, _sender_evict_rx) = oneshot::channel();
+        handle
+            .connect(sender_queue, sender_evict_tx, canvas_id.clone(), sender_id.clone(), "alice".to_owned(), CanvasRole::Writer)
+            .await;
+
+        let slow_queue = Arc::new(OutboundQueue::new(OUTBOUND_QUEUE_CAPACITY));
+        let (slow_evict_tx, mut slow_evict_rx) = oneshot::channel();
+        handle
+            .connect(slow_queue, slow_evict_tx, canvas_id.clone(), slow_id.clone(), "bob".to_owned(), CanvasRole::Writer)
+            .await;
+        handle
+            .subscribe(canvas_id.clone(), slow_id.clone(), Subscription { layers: Default::default() })
+            .await;
+
+        // stroke-end ops are never coalesced (no replace key), so each one takes a queue slot
+        // until the slow participant's queue is full and they're evicted
+        for _ in 0..=OUTBOUND_QUEUE_CAPACITY {
+            handle
+                .broadcast_binary_event(canvas_id.clone(), sender_id.clone(), 0, None, Bytes::from_static(&[0x02, 0, 0, 0, 0, 0, 0]))
+                .await;
+        }
+
+        let reason = slow_evict_rx
+            .try_recv()
+            .expect("slow participant should have been evicted");
+        assert_eq!(reason.code, CloseCode::Policy);
+        assert!(!handle.is_connected(canvas_id, slow_id).await);
+    }
+
+    /// A reported latency becomes readable through the handle, and an unreported one reads back
+    /// as `None` rather than being tracked write-only.
+    #[tokio::test]
+    async fn reported_latency_is_readable_through_handle() {
+        let (server, handle) = CanvasSocketServer::new();
+        tokio::spawn(server.run());
+
+        let canvas_id = CanvasId("canvas-1".to_owned());
+        let user_id = UserId("user-1".to_owned());
+
+        let queue = Arc::new(OutboundQueue::new(OUTBOUND_QUEUE_CAPACITY));
+        let (evict_tx, _evict_rx) = oneshot::channel();
+        handle
+            .connect(queue, evict_tx, canvas_id.clone(), user_id.clone(), "alice".to_owned(), CanvasRole::Writer)
+            .await;
+
+        assert_eq!(handle.latency(canvas_id.clone(), user_id.clone()).await, None);
+
+        handle.report_latency(canvas_id.clone(), user_id.clone(), Duration::from_millis(42));
+
+        // report_latency isn't acked, but it and the following latency() call share the same
+        // single-consumer FIFO command channel, so the server processes them in send order
+        assert_eq!(handle.latency(canvas_id, user_id).await, Some(Duration::from_millis(42)));
+    }
+}
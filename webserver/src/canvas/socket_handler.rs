@@ -1,17 +1,23 @@
 use std::{
     pin::pin,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
-use actix_ws::AggregatedMessage;
-use futures_util::{
-    future::{select, Either},
-    StreamExt as _,
-};
-use tokio::{sync::mpsc, time::interval};
+use actix_ws::{AggregatedMessage, CloseCode, CloseReason, Closed};
+use futures_util::{Stream, StreamExt as _};
+use tokio::sync::oneshot;
+use tokio::time::interval;
 
-use crate::{authentication::JWTUser, canvas::server::CanvasSocketServerHandle, userstore::UserId};
+use crate::{
+    authentication::JWTUser,
+    canvas::server::{CanvasSocketServerHandle, OutboundMessage, OUTBOUND_QUEUE_CAPACITY},
+    userstore::UserId,
+};
 
+use super::handshake::ClientHandshake;
+use super::outbound_queue::OutboundQueue;
+use super::proto::CanvasOpHeader;
 use super::store::{CanvasClaim, CanvasId};
 
 /// How often heartbeat pings are sent
@@ -20,101 +26,222 @@ const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 /// How long before lack of client response causes a timeout
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// Size in bytes of a heartbeat ping payload (a big-endian `u128` of nanos since connect)
+const PING_PAYLOAD_LEN: usize = std::mem::size_of::<u128>();
+
+/// The outbound half of a websocket connection, abstracted so the connection loop's
+/// disconnect-on-write-failure behavior can be driven against a fake in tests instead of a real
+/// `actix_ws::Session`.
+trait SessionSink: Sized {
+    async fn text(&mut self, msg: String) -> Result<(), Closed>;
+    async fn binary(&mut self, msg: bytes::Bytes) -> Result<(), Closed>;
+    async fn ping(&mut self, msg: &[u8]) -> Result<(), Closed>;
+    async fn pong(&mut self, msg: &[u8]) -> Result<(), Closed>;
+    async fn close(self, reason: Option<CloseReason>) -> Result<(), Closed>;
+}
+
+impl SessionSink for actix_ws::Session {
+    async fn text(&mut self, msg: String) -> Result<(), Closed> {
+        actix_ws::Session::text(self, msg).await
+    }
+
+    async fn binary(&mut self, msg: bytes::Bytes) -> Result<(), Closed> {
+        actix_ws::Session::binary(self, msg).await
+    }
+
+    async fn ping(&mut self, msg: &[u8]) -> Result<(), Closed> {
+        actix_ws::Session::ping(self, msg).await
+    }
+
+    async fn pong(&mut self, msg: &[u8]) -> Result<(), Closed> {
+        actix_ws::Session::pong(self, msg).await
+    }
+
+    async fn close(self, reason: Option<CloseReason>) -> Result<(), Closed> {
+        actix_ws::Session::close(self, reason).await
+    }
+}
+
 /// Echo text & binary messages received from the client, respond to ping messages, and monitor
 /// connection health to detect network issues and free up resources.
 pub async fn start_canvas_websocket_connection(
     chat_server: CanvasSocketServerHandle,
-    mut session: actix_ws::Session,
+    session: actix_ws::Session,
     msg_stream: actix_ws::MessageStream,
     canvas_id: CanvasId,
     user: JWTUser,
-    claim: CanvasClaim
+    claim: CanvasClaim,
 ) {
-    let mut last_heartbeat = Instant::now();
+    let msg_stream = msg_stream
+        .max_frame_size(128 * 1024)
+        .aggregate_continuations()
+        .max_continuation_size(2 * 1024 * 1024);
+
+    drive_canvas_websocket_connection(chat_server, session, msg_stream, canvas_id, user, claim).await
+}
+
+/// The actual connection loop, generic over the outbound session and the inbound message stream
+/// so it can be exercised in tests without a real socket.
+async fn drive_canvas_websocket_connection(
+    chat_server: CanvasSocketServerHandle,
+    mut session: impl SessionSink,
+    msg_stream: impl Stream<Item = Result<AggregatedMessage, actix_ws::ProtocolError>>,
+    canvas_id: CanvasId,
+    user: JWTUser,
+    claim: CanvasClaim,
+) {
+    let connected_at = Instant::now();
+    let mut last_heartbeat = connected_at;
     let mut interval = interval(HEARTBEAT_INTERVAL);
 
-    let (message_tx, mut message_rx) = mpsc::unbounded_channel();
+    // payload of the last ping we sent, so a pong can be matched back to it and a stray or
+    // forged pong (not from a ping we actually sent) can be ignored
+    let mut last_ping_sent_at: Option<Duration> = None;
+
+    // the first data frame a client sends must be a subscribe handshake; until then, no other
+    // text/binary content is accepted
+    let mut subscribed = false;
+
+    // bounded, with high-frequency events like cursor moves coalesced in place, so a slow
+    // consumer can't force us to buffer every broadcast for it indefinitely; the server evicts
+    // a participant instead of blocking once this fills up
+    let outbound_queue = Arc::new(OutboundQueue::new(OUTBOUND_QUEUE_CAPACITY));
+    let (evict_tx, mut evict_rx) = oneshot::channel();
+
     chat_server.connect(
-        message_tx,
+        outbound_queue.clone(),
+        evict_tx,
         canvas_id.clone(),
         user.id.clone(),
         user.username.clone(),
         claim.r
     ).await;
 
-    let msg_stream = msg_stream
-        .max_frame_size(128 * 1024)
-        .aggregate_continuations()
-        .max_continuation_size(2 * 1024 * 1024);
-
     let mut msg_stream = pin!(msg_stream);
 
     let close_reason = loop {
-        // most of the futures we process need to be stack-pinned to work with select()
-        let tick = pin!(interval.tick());
-        let msg_rx = pin!(message_rx.recv());
-
-        // TODO: nested select is pretty gross for readability on the match
-        let messages = pin!(select(msg_stream.next(), msg_rx));
-
-        match select(messages, tick).await {
+        tokio::select! {
             // commands & messages received from client
-            Either::Left((Either::Left((Some(Ok(msg)), _)), _)) => {
+            msg = msg_stream.next() => {
                 match msg {
-                    AggregatedMessage::Ping(bytes) => {
-                        last_heartbeat = Instant::now();
-                        session.pong(&bytes).await.unwrap();
-                    }
+                    Some(Ok(msg)) => match msg {
+                        AggregatedMessage::Ping(bytes) => {
+                            last_heartbeat = Instant::now();
 
-                    AggregatedMessage::Pong(_) => {
-                        last_heartbeat = Instant::now();
-                    }
+                            if session.pong(&bytes).await.is_err() {
+                                // peer closed mid-write; nothing more to send
+                                break None;
+                            }
+                        }
 
-                    AggregatedMessage::Text(text) => {
-                        process_user_socket_msg(&chat_server, &text, canvas_id.clone(), user.id.clone()).await;
-                    }
+                        AggregatedMessage::Pong(bytes) => {
+                            last_heartbeat = Instant::now();
 
-                    AggregatedMessage::Binary(_bin) => {
-                        println!("unexpected binary message");
+                            if let Some(rtt) = decode_pong_rtt(&bytes, last_ping_sent_at, connected_at.elapsed()) {
+                                chat_server.report_latency(canvas_id.clone(), user.id.clone(), rtt);
+                            }
+
+                            last_ping_sent_at = None;
+                        }
+
+                        AggregatedMessage::Text(text) if !subscribed => {
+                            match serde_json::from_str::<ClientHandshake>(text.trim()) {
+                                Ok(handshake) => {
+                                    chat_server.subscribe(canvas_id.clone(), user.id.clone(), handshake.into()).await;
+                                    subscribed = true;
+                                }
+                                Err(err) => {
+                                    println!("{} in {canvas_id} sent data before a valid handshake: {err}", user.id);
+                                    break Some(CloseReason {
+                                        code: CloseCode::Policy,
+                                        description: Some("first message must be a subscribe handshake".to_owned()),
+                                    });
+                                }
+                            }
+                        }
+
+                        AggregatedMessage::Text(text) => {
+                            process_user_socket_msg(&chat_server, &text, canvas_id.clone(), user.id.clone()).await;
+                        }
+
+                        AggregatedMessage::Binary(_bin) if !subscribed => {
+                            println!("{} in {canvas_id} sent data before a valid handshake", user.id);
+                            break Some(CloseReason {
+                                code: CloseCode::Policy,
+                                description: Some("first message must be a subscribe handshake".to_owned()),
+                            });
+                        }
+
+                        AggregatedMessage::Binary(bin) => {
+                            match CanvasOpHeader::parse(&bin) {
+                                Ok(header) => {
+                                    let replace_key = header.replace_key(&user.id);
+                                    chat_server
+                                        .broadcast_binary_event(canvas_id.clone(), user.id.clone(), header.layer_id, replace_key, bin)
+                                        .await;
+                                }
+                                Err(err) => {
+                                    println!("dropping malformed binary canvas op from {}: {err:?}", user.id);
+                                }
+                            }
+                        }
+
+                        AggregatedMessage::Close(reason) => break reason,
+                    },
+
+                    // client WebSocket stream error
+                    Some(Err(err)) => {
+                        println!("{}", err);
+                        break None;
                     }
 
-                    AggregatedMessage::Close(reason) => break reason,
+                    // client WebSocket stream ended
+                    None => break None,
                 }
             }
 
-            // client WebSocket stream error
-            Either::Left((Either::Left((Some(Err(err)), _)), _)) => {
-                println!("{}", err);
-                break None;
-            }
-
-            // client WebSocket stream ended
-            Either::Left((Either::Left((None, _)), _)) => break None,
+            // events received from other room participants
+            outbound_msg = outbound_queue.recv() => {
+                let sent = match outbound_msg {
+                    OutboundMessage::Text(text) => session.text(text).await,
+                    OutboundMessage::Binary(bin) => session.binary(bin).await,
+                };
 
-            // chat messages received from other room participants
-            Either::Left((Either::Right((Some(chat_msg), _)), _)) => {
-                session.text(chat_msg).await.unwrap();
+                // a send failure means the peer vanished mid-write; this is a normal early
+                // disconnect, not a bug, so tear the connection down rather than panicking
+                if sent.is_err() {
+                    break None;
+                }
             }
 
-            // all connection's message senders were dropped
-            Either::Left((Either::Right((None, _)), _)) => unreachable!(
-                "all connection message senders were dropped; chat server may have panicked"
-            ),
+            // server evicted us for falling too far behind on this canvas's broadcasts
+            reason = &mut evict_rx => {
+                if let Ok(reason) = reason {
+                    break Some(reason);
+                }
+            }
 
             // heartbeat internal tick
-            Either::Right((_inst, _)) => {
+            _ = interval.tick() => {
                 // if no heartbeat ping/pong received recently, close the connection
                 if Instant::now().duration_since(last_heartbeat) > CLIENT_TIMEOUT {
                     println!("User {} in {canvas_id} timed out", user.id);
                     break None;
                 }
 
-                // send heartbeat ping
-                let _ = session.ping(b"").await;
+                // send heartbeat ping, encoding when we sent it so the matching pong can be
+                // turned into a round-trip latency measurement
+                let sent_at = connected_at.elapsed();
+                last_ping_sent_at = Some(sent_at);
+
+                if session.ping(&sent_at.as_nanos().to_be_bytes()).await.is_err() {
+                    // peer closed mid-write; detect it now instead of waiting out the timeout
+                    break None;
+                }
             }
         };
     };
-    
+
     chat_server.disconnect(canvas_id, user.id.clone());
 
     // attempt to close connection gracefully
@@ -135,4 +262,152 @@ async fn process_user_socket_msg(
 
     // session.text(response).await.unwrap();
     chat_server.broadcast_event(canvas_id, user_id, msg).await;
-}
\ No newline at end of file
+}
+
+/// Turns a pong payload into a round-trip latency, or `None` if it should be ignored: wrong
+/// length, doesn't match the ping we last sent, or claims to have been sent after `now`
+/// (which would mean either clock weirdness or a forged payload).
+fn decode_pong_rtt(bytes: &[u8], last_ping_sent_at: Option<Duration>, now: Duration) -> Option<Duration> {
+    let sent_at = last_ping_sent_at?;
+
+    let bytes: [u8; PING_PAYLOAD_LEN] = bytes.try_into().ok()?;
+    let claimed_sent_at = Duration::from_nanos(u128::from_be_bytes(bytes).try_into().ok()?);
+
+    if claimed_sent_at != sent_at || claimed_sent_at > now {
+        return None;
+    }
+
+    Some(now - claimed_sent_at)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::handshake::Subscription;
+    use crate::canvas::server::CanvasSocketServer;
+    use crate::canvas::store::CanvasRole;
+
+    /// A session whose writes always fail, standing in for a peer that vanished mid-broadcast.
+    struct FailingSession;
+
+    impl SessionSink for FailingSession {
+        async fn text(&mut self, _msg: String) -> Result<(), Closed> {
+            Err(Closed)
+        }
+
+        async fn binary(&mut self, _msg: bytes::Bytes) -> Result<(), Closed> {
+            Err(Closed)
+        }
+
+        async fn ping(&mut self, _msg: &[u8]) -> Result<(), Closed> {
+            Ok(())
+        }
+
+        async fn pong(&mut self, _msg: &[u8]) -> Result<(), Closed> {
+            Ok(())
+        }
+
+        async fn close(self, _reason: Option<CloseReason>) -> Result<(), Closed> {
+            Ok(())
+        }
+    }
+
+    /// A connection whose write to the peer fails mid-broadcast (the peer vanished) tears
+    /// itself down and is removed from the canvas promptly, instead of being left registered
+    /// until the next heartbeat timeout notices.
+    #[tokio::test]
+    async fn connection_disconnects_on_write_failure() {
+        let (server, chat_server) = CanvasSocketServer::new();
+        tokio::spawn(server.run());
+
+        let canvas_id = CanvasId("canvas-1".to_owned());
+        let victim = JWTUser { id: UserId("victim".to_owned()), username: "victim".to_owned() };
+        let claim = CanvasClaim { r: CanvasRole::Writer };
+
+        let connection = tokio::spawn(drive_canvas_websocket_connection(
+            chat_server.clone(),
+            FailingSession,
+            futures_util::stream::pending::<Result<AggregatedMessage, actix_ws::ProtocolError>>(),
+            canvas_id.clone(),
+            victim.clone(),
+            claim,
+        ));
+
+        // the connection task registers itself asynchronously; wait for that before triggering
+        // a broadcast, since send order across two independent tasks isn't otherwise guaranteed
+        while !chat_server.is_connected(canvas_id.clone(), victim.id.clone()).await {
+            tokio::task::yield_now().await;
+        }
+
+        chat_server
+            .subscribe(canvas_id.clone(), victim.id.clone(), Subscription { layers: Default::default() })
+            .await;
+
+        let sender_id = UserId("sender".to_owned());
+        let sender_queue = Arc::new(OutboundQueue::new(OUTBOUND_QUEUE_CAPACITY));
+        let (sender_evict_tx, _sender_evict_rx) = oneshot::channel();
+        chat_server
+            .connect(sender_queue, sender_evict_tx, canvas_id.clone(), sender_id.clone(), "alice".to_owned(), CanvasRole::Writer)
+            .await;
+
+        // relayed to the victim, whose write fails and should tear its connection down
+        chat_server.broadcast_event(canvas_id.clone(), sender_id, "hi").await;
+
+        connection.await.expect("connection task should not panic");
+
+        assert!(!chat_server.is_connected(canvas_id, victim.id).await);
+    }
+
+    fn payload(sent_at: Duration) -> [u8; PING_PAYLOAD_LEN] {
+        sent_at.as_nanos().to_be_bytes()
+    }
+
+    #[test]
+    fn matching_pong_yields_round_trip_latency() {
+        let sent_at = Duration::from_millis(10);
+        let now = Duration::from_millis(35);
+
+        let rtt = decode_pong_rtt(&payload(sent_at), Some(sent_at), now);
+
+        assert_eq!(rtt, Some(Duration::from_millis(25)));
+    }
+
+    #[test]
+    fn pong_not_matching_last_ping_is_ignored() {
+        let sent_at = Duration::from_millis(10);
+        let now = Duration::from_millis(35);
+
+        let rtt = decode_pong_rtt(&payload(Duration::from_millis(11)), Some(sent_at), now);
+
+        assert_eq!(rtt, None);
+    }
+
+    #[test]
+    fn pong_with_no_outstanding_ping_is_ignored() {
+        let now = Duration::from_millis(35);
+
+        let rtt = decode_pong_rtt(&payload(Duration::from_millis(10)), None, now);
+
+        assert_eq!(rtt, None);
+    }
+
+    #[test]
+    fn pong_claiming_to_be_sent_after_now_is_ignored() {
+        let sent_at = Duration::from_millis(40);
+        let now = Duration::from_millis(35);
+
+        let rtt = decode_pong_rtt(&payload(sent_at), Some(sent_at), now);
+
+        assert_eq!(rtt, None);
+    }
+
+    #[test]
+    fn wrong_length_payload_is_ignored() {
+        let sent_at = Duration::from_millis(10);
+        let now = Duration::from_millis(35);
+
+        let rtt = decode_pong_rtt(&[0, 1, 2], Some(sent_at), now);
+
+        assert_eq!(rtt, None);
+    }
+}
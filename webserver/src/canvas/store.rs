@@ -0,0 +1,27 @@
+use std::fmt;
+
+/// Identifies a canvas (drawing board/room) that participants connect to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CanvasId(pub String);
+
+impl fmt::Display for CanvasId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Access level granted to a participant for a canvas, as encoded in their join token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanvasRole {
+    /// Can only observe the canvas. Not currently enforced on the broadcast/receive path; the
+    /// role is recorded per participant so write enforcement can be added later.
+    Reader,
+    /// Can observe and submit drawing operations.
+    Writer,
+}
+
+/// Claims extracted from the signed token a client presents when joining a canvas.
+#[derive(Debug, Clone)]
+pub struct CanvasClaim {
+    pub r: CanvasRole,
+}